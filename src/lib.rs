@@ -6,26 +6,32 @@ use pyo3::ffi::{
     CO_VARKEYWORDS,
 };
 use pyo3::prelude::*;
-use pyo3::types::{PyDict, PyObjectRef, PyString};
+use pyo3::types::{PyDict, PyList, PyObjectRef, PyString, PyTuple};
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use serde_json;
 
+use rusqlite::{Connection, NO_PARAMS};
+
 use lazy_static::lazy_static;
 
+use glob::Pattern;
+
 use slog::info;
 use sloggers::file::FileLoggerBuilder;
 use sloggers::types::{OverflowStrategy, Severity};
 use sloggers::Build;
 
-use std::borrow::Cow;
 use std::boxed::Box;
+use std::collections::{BTreeMap, BTreeSet};
 use std::env;
 use std::ffi::CString;
+use std::fs;
+use std::io::Write as _;
 use std::ops::Deref;
 use std::os::raw::c_int;
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
 
 type _PyFrameEvalFunction = unsafe extern "C" fn(*mut PyFrameObject, c_int) -> *mut PyObject;
 
@@ -34,22 +40,82 @@ cpp! {{
     #include <Python.h>
 }}
 
-// We can safely have a global mutable like this since CPython has a GIL,
-// therefore only one thread can ever be running a frame.
-static mut FRAMES: Option<Mutex<Vec<FrameInfo>>> = None;
+// The central store is only touched at flush boundaries, never on the hot
+// capture path (per-thread buffers handle concurrent capture). Every access
+// locks the `Mutex` via [`store_lock`] rather than relying on the GIL, so the
+// store stays correctly serialised on free-threaded / sub-interpreter builds.
+static mut STORE: Option<Mutex<Box<dyn TraceStore>>> = None;
 
 lazy_static! {
     static ref CURRENT_DIR: PathBuf = env::current_dir().unwrap();
+    /// Capture filter consulted by `frame_printer` for every candidate frame.
+    /// Populated by `hook`; empty by default, which preserves the original
+    /// "frames under the current working directory" behaviour.
+    static ref CONFIG: RwLock<TraceConfig> = RwLock::new(TraceConfig::default());
+    /// Registry of every thread's capture buffer. The flush path walks this to
+    /// drain buffers owned by threads other than the one doing the flush, which
+    /// a plain `thread_local!` could not reach on its own.
+    static ref BUFFERS: Mutex<Vec<Arc<Mutex<Vec<FrameInfo>>>>> = Mutex::new(Vec::new());
+}
+
+thread_local! {
+    /// Per-thread capture buffer. Writing here instead of straight into the
+    /// global store avoids a shared lock on the hot path and keeps capture
+    /// correct on free-threaded (nogil) builds and across sub-interpreters,
+    /// where the GIL no longer serialises frame execution. Registered into
+    /// [`BUFFERS`] on first use so it can be drained centrally at flush time.
+    static LOCAL_FRAMES: Arc<Mutex<Vec<FrameInfo>>> = {
+        let buf = Arc::new(Mutex::new(Vec::new()));
+        BUFFERS.lock().unwrap().push(buf.clone());
+        buf
+    };
+}
+
+/// Runtime capture filter. Filenames are matched against glob `include` /
+/// `exclude` patterns and function names (the code object's `co_name`) against
+/// `names`; module names are not matched against `names`. An empty
+/// `include` keeps the original default of tracing frames under the current
+/// working directory (plus `<stdin>`).
+#[derive(Default)]
+struct TraceConfig {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+    names: Vec<Pattern>,
 }
 
-#[derive(Serialize, Debug)]
+impl TraceConfig {
+    /// Decide whether a frame in `file` with function `name` should be traced.
+    fn should_trace(&self, name: &str, file: &str) -> bool {
+        // Synthetic frames (`<module>`, `<listcomp>`, ...) are never traced.
+        if name.starts_with('<') {
+            return false;
+        }
+        if self.include.is_empty() {
+            let cwd = CURRENT_DIR.to_str().unwrap();
+            if !(file.starts_with(cwd) || file == "<stdin>") {
+                return false;
+            }
+        } else if !self.include.iter().any(|p| p.matches(file)) {
+            return false;
+        }
+        if self.exclude.iter().any(|p| p.matches(file)) {
+            return false;
+        }
+        if !self.names.is_empty() && !self.names.iter().any(|p| p.matches(name)) {
+            return false;
+        }
+        true
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct Arg {
     name: String,
     typ: String,
     kind: ArgKind,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 enum ArgKind {
     Positional,
     StarArgs,
@@ -57,6 +123,117 @@ enum ArgKind {
     StarKwargs,
 }
 
+/// Maximum number of elements sampled from a container when inferring its
+/// element type, and the deepest level of nesting we recurse into before
+/// falling back to the bare container name.
+const SAMPLE_LIMIT: usize = 10;
+const MAX_DEPTH: usize = 3;
+
+/// How many observations a thread buffers before it drains them into the
+/// central store. Draining on this threshold (rather than only at exit) keeps
+/// the per-thread buffers bounded and feeds the store — and, for the SQLite
+/// backend, the disk — incrementally during steady-state tracing.
+const DRAIN_INTERVAL: usize = 256;
+
+/// Sample up to [`SAMPLE_LIMIT`] objects and union their recursively-described
+/// types into a set suitable for [`render_types`].
+fn sample_types<'a, I>(items: I, depth: usize) -> BTreeSet<String>
+where
+    I: Iterator<Item = &'a PyObjectRef>,
+{
+    let mut types = BTreeSet::new();
+    for obj in items.take(SAMPLE_LIMIT) {
+        types.insert(describe_obj(obj, depth + 1));
+    }
+    types
+}
+
+/// Render the type of a single Python object, recursively sampling container
+/// element types into parameterized generics (`List[int]`, `Dict[str, int]`,
+/// fixed-length `Tuple[int, str]`, ...). Falls back to the bare container name
+/// for empty collections or once `depth` reaches [`MAX_DEPTH`].
+fn describe_obj(obj: &PyObjectRef, depth: usize) -> String {
+    let name = obj.get_type().name();
+    let bare = String::from(name.deref());
+    if depth >= MAX_DEPTH {
+        return bare;
+    }
+    match name.deref() {
+        "list" => match obj.extract::<&PyList>() {
+            Ok(list) if list.len() > 0 => {
+                format!("List[{}]", render_types(&sample_types(list.iter(), depth)))
+            }
+            _ => bare,
+        },
+        "tuple" => match obj.extract::<&PyTuple>() {
+            Ok(tuple) if tuple.len() > 0 && tuple.len() <= SAMPLE_LIMIT => {
+                let parts: Vec<String> =
+                    tuple.iter().map(|e| describe_obj(e, depth + 1)).collect();
+                format!("Tuple[{}]", parts.join(", "))
+            }
+            _ => bare,
+        },
+        "set" | "frozenset" => {
+            let label = if name.deref() == "set" {
+                "Set"
+            } else {
+                "FrozenSet"
+            };
+            match obj.iter() {
+                Ok(it) => {
+                    let elems = sample_types(it.filter_map(Result::ok), depth);
+                    if elems.is_empty() {
+                        bare
+                    } else {
+                        format!("{}[{}]", label, render_types(&elems))
+                    }
+                }
+                Err(_) => bare,
+            }
+        }
+        "dict" => match obj.extract::<&PyDict>() {
+            Ok(dict) if dict.len() > 0 => {
+                let mut keys = BTreeSet::new();
+                let mut vals = BTreeSet::new();
+                for (k, v) in dict.iter().take(SAMPLE_LIMIT) {
+                    keys.insert(describe_obj(k, depth + 1));
+                    vals.insert(describe_obj(v, depth + 1));
+                }
+                format!(
+                    "Dict[{}, {}]",
+                    render_types(&keys),
+                    render_types(&vals)
+                )
+            }
+            _ => bare,
+        },
+        _ => bare,
+    }
+}
+
+/// Describe a `*args` / `**kwargs` slot by the union of its *element* types
+/// rather than the container object itself. `describe_obj` would render the
+/// args tuple as a fixed-length `Tuple[..]` (implying each variadic argument is
+/// a tuple), so for the star slots we sample the contained values instead and
+/// emit e.g. `int` / `Union[int, str]`.
+fn describe_star_type(obj: &PyObjectRef, kind: ArgKind) -> String {
+    let elems = match kind {
+        ArgKind::StarKwargs => match obj.extract::<&PyDict>() {
+            Ok(dict) => sample_types(dict.iter().map(|(_, v)| v), 0),
+            Err(_) => return describe_obj(obj, 0),
+        },
+        _ => match obj.iter() {
+            Ok(it) => sample_types(it.filter_map(Result::ok), 0),
+            Err(_) => return describe_obj(obj, 0),
+        },
+    };
+    if elems.is_empty() {
+        String::from("Any")
+    } else {
+        render_types(&elems)
+    }
+}
+
 /// Extract the arguments from the frame->f_locals (a mapping of name to value)
 /// This is inspired by the code in inspect.py
 fn locals_to_args<'a>(
@@ -75,29 +252,26 @@ fn locals_to_args<'a>(
     let varkwargs = (coflags & CO_VARKEYWORDS) != 0;
     for (pyname, pyval) in positional {
         let name = pyname.to_string();
-        let val = pyval.get_type().name();
         args.push(Arg {
             name: name,
-            typ: String::from(val.deref()),
+            typ: describe_obj(*pyval, 0),
             kind: ArgKind::Positional,
         });
     }
     if varargs {
         let (pyname, pyval) = items[argc + kwargc];
         let name = pyname.to_string();
-        let val = pyval.get_type().name();
         args.push(Arg {
             name: name,
-            typ: String::from(val.deref()),
+            typ: describe_star_type(pyval, ArgKind::StarArgs),
             kind: ArgKind::StarArgs,
         });
     }
     for (pyname, pyval) in keywordonly {
         let name = pyname.to_string();
-        let val = pyval.get_type().name();
         args.push(Arg {
             name: name,
-            typ: String::from(val.deref()),
+            typ: describe_obj(*pyval, 0),
             kind: ArgKind::KeywordOnly,
         });
     }
@@ -109,22 +283,30 @@ fn locals_to_args<'a>(
         };
         let (pyname, pyval) = items[index];
         let name = pyname.to_string();
-        let val = pyval.get_type().name();
         args.push(Arg {
             name: name,
-            typ: String::from(val.deref()),
+            typ: describe_star_type(pyval, ArgKind::StarKwargs),
             kind: ArgKind::StarKwargs,
         });
     }
     Arc::new(args)
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct FrameInfo {
     name: String,
     filename: String,
     args: Arc<Vec<Arg>>,
     returns: String,
+    /// Name of the exception class observed propagating out of the call, or
+    /// `None` when the call returned normally.
+    raises: Option<String>,
+    /// First source line of the code object. Paired with `name` it distinguishes
+    /// same-named functions (e.g. a `run` method on two classes) in one file,
+    /// standing in for a qualified name the target CPython code object — which
+    /// predates `co_qualname` — does not expose.
+    #[serde(default)]
+    firstlineno: i32,
 }
 
 impl<'a> FrameInfo {
@@ -136,6 +318,8 @@ impl<'a> FrameInfo {
         argc: i32,
         kwargc: i32,
         coflags: i32,
+        raises: Option<String>,
+        firstlineno: i32,
     ) -> FrameInfo {
         let args = locals_to_args(locals, argc as usize, kwargc as usize, coflags);
         FrameInfo {
@@ -143,15 +327,404 @@ impl<'a> FrameInfo {
             filename: String::from(filename),
             args: args,
             returns: String::from(returns),
+            raises: raises,
+            firstlineno: firstlineno,
+        }
+    }
+}
+
+/// Resolve the class name of a fetched exception, e.g. `ValueError`.
+fn exc_type_name(py: Python, err: &PyErr) -> Option<String> {
+    err.ptype
+        .as_ref(py)
+        .getattr("__name__")
+        .ok()
+        .and_then(|name| name.extract::<String>().ok())
+}
+
+/// A sink for recorded frame observations. `record` is called for every
+/// observed call (under the GIL), while `flush` is called periodically and
+/// at exit to persist whatever has accumulated. Implementations decide how to
+/// buffer, deduplicate and store the data.
+trait TraceStore: Send {
+    fn record(&mut self, frame: &FrameInfo);
+    fn flush(&mut self);
+    /// Materialise every recorded observation, for in-process consumers such
+    /// as stub generation.
+    fn snapshot(&self) -> Vec<FrameInfo>;
+    /// Discard everything recorded so far, so a new trace session can start in
+    /// the same interpreter.
+    fn clear(&mut self);
+}
+
+/// The dedup key for a single call: source file, function name and first line
+/// (standing in for a qualified name), and the comma-joined argument type
+/// signature.
+fn call_key(frame: &FrameInfo) -> String {
+    let signature = frame
+        .args
+        .iter()
+        .map(|a| a.typ.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!(
+        "{}|{}|{}|{}",
+        frame.filename, frame.name, frame.firstlineno, signature
+    )
+}
+
+/// In-memory store that buffers every observation and dumps the full set as a
+/// JSON blob on flush — the original behaviour, kept as the default backend.
+struct JsonStore {
+    frames: Vec<FrameInfo>,
+    path: String,
+}
+
+impl JsonStore {
+    fn new(path: String) -> JsonStore {
+        JsonStore {
+            frames: Vec::new(),
+            path: path,
         }
     }
 }
 
-/// Get the type of a Python object pointer
-fn get_type<'a>(py: Python<'a>, obj: *mut PyObject) -> Cow<'a, str> {
+impl TraceStore for JsonStore {
+    fn record(&mut self, frame: &FrameInfo) {
+        self.frames.push(frame.clone());
+    }
+
+    fn flush(&mut self) {
+        let logger = {
+            let mut builder = FileLoggerBuilder::new(&self.path);
+            builder.level(Severity::Info);
+            builder.overflow_strategy(OverflowStrategy::Block);
+            builder.channel_size(4096);
+            builder.build().unwrap()
+        };
+        info!(logger, "{}", serde_json::to_string(&self.frames).unwrap());
+        info!(logger, "Captured {} frames", self.frames.len());
+    }
+
+    fn snapshot(&self) -> Vec<FrameInfo> {
+        self.frames.clone()
+    }
+
+    fn clear(&mut self) {
+        self.frames.clear();
+    }
+}
+
+/// SQLite-backed store for long-running processes. Observations are
+/// deduplicated in memory by call signature (with a running hit count) and
+/// batched to disk, so a process that runs for hours — or is killed — keeps
+/// the types it has already seen instead of losing everything at exit.
+struct SqliteStore {
+    conn: Connection,
+    /// Representative frame (serialized) and this batch's hit count, keyed by
+    /// [`call_key`].
+    pending: BTreeMap<String, (String, i64)>,
+    batch_size: usize,
+    /// Observations recorded since the last flush, counted independently of how
+    /// many distinct signatures they collapse into. Without this a hot function
+    /// called millions of times produces a single pending key and would never
+    /// reach `batch_size`, so nothing hits disk until `atexit`.
+    pending_rows: usize,
+}
+
+impl SqliteStore {
+    fn open(path: &str) -> rusqlite::Result<SqliteStore> {
+        let conn = Connection::open(path)?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS calls (
+                 key   TEXT PRIMARY KEY,
+                 frame TEXT NOT NULL,
+                 hits  INTEGER NOT NULL
+             )",
+            NO_PARAMS,
+        )?;
+        Ok(SqliteStore {
+            conn,
+            pending: BTreeMap::new(),
+            // Kept in step with the per-thread drain cadence (`DRAIN_INTERVAL`)
+            // so that batched records reach disk promptly as drains arrive,
+            // rather than accumulating in the dedup map until exit.
+            batch_size: 256,
+            pending_rows: 0,
+        })
+    }
+}
+
+impl TraceStore for SqliteStore {
+    fn record(&mut self, frame: &FrameInfo) {
+        let key = call_key(frame);
+        let entry = self
+            .pending
+            .entry(key)
+            .or_insert_with(|| (serde_json::to_string(frame).unwrap(), 0));
+        entry.1 += 1;
+        self.pending_rows += 1;
+        // Flush on either enough distinct signatures or enough total rows, so a
+        // few hot signatures still get persisted periodically.
+        if self.pending.len() >= self.batch_size || self.pending_rows >= self.batch_size {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        self.pending_rows = 0;
+        for (key, (frame, hits)) in std::mem::take(&mut self.pending) {
+            // Upsert: new signatures are inserted, repeats bump the hit count.
+            let _ = self.conn.execute(
+                "INSERT INTO calls (key, frame, hits) VALUES (?1, ?2, ?3)
+                 ON CONFLICT(key) DO UPDATE SET hits = hits + ?3",
+                &[&key as &dyn rusqlite::ToSql, &frame, &hits],
+            );
+        }
+    }
+
+    fn snapshot(&self) -> Vec<FrameInfo> {
+        let mut stmt = match self.conn.prepare("SELECT frame FROM calls") {
+            Ok(stmt) => stmt,
+            Err(_) => return Vec::new(),
+        };
+        let rows = match stmt.query_map(NO_PARAMS, |row| row.get::<_, String>(0)) {
+            Ok(rows) => rows,
+            Err(_) => return Vec::new(),
+        };
+        let mut frames: Vec<FrameInfo> = rows
+            .filter_map(Result::ok)
+            .filter_map(|json| serde_json::from_str::<FrameInfo>(&json).ok())
+            .collect();
+        // Include observations still waiting in the current batch; signatures
+        // already on disk are represented by their stored row.
+        let stored: BTreeSet<String> = frames.iter().map(call_key).collect();
+        frames.extend(
+            self.pending
+                .iter()
+                .filter(|(key, _)| !stored.contains(*key))
+                .filter_map(|(_, (json, _))| serde_json::from_str::<FrameInfo>(json).ok()),
+        );
+        frames
+    }
+
+    fn clear(&mut self) {
+        self.pending.clear();
+        self.pending_rows = 0;
+        let _ = self.conn.execute("DELETE FROM calls", NO_PARAMS);
+    }
+}
+
+/// Lock the global store, panicking if it has not been initialised by the
+/// module import yet. Taking the `Mutex` guard (rather than `get_mut` on the
+/// `static mut`) keeps concurrent `get_frames` / `dump_stubs` / `reset` /
+/// atexit callers from aliasing `&mut` to the store on nogil builds.
+unsafe fn store_lock<'a>() -> std::sync::MutexGuard<'a, Box<dyn TraceStore>> {
+    match STORE.as_ref() {
+        Some(store) => store.lock().unwrap(),
+        None => panic!("Trace store is not initialised"),
+    }
+}
+
+/// Drain every per-thread buffer into `store`, merging all captured frames
+/// regardless of which thread or sub-interpreter produced them.
+fn drain_buffers(store: &mut Box<dyn TraceStore>) {
+    let buffers = BUFFERS.lock().unwrap();
+    for buf in buffers.iter() {
+        let mut frames = buf.lock().unwrap();
+        for frame in frames.drain(..) {
+            store.record(&frame);
+        }
+    }
+}
+
+/// Aggregated type observations for a single function, keyed by its
+/// `(filename, name, first line)` while walking the recorded frames.
+struct FunctionStub {
+    name: String,
+    /// Observed types per parameter in first-seen order, tagged with the kind
+    /// so the rendered signature can reconstruct `*args` / `**kwargs`.
+    params: Vec<(String, ArgKind, BTreeSet<String>)>,
+    returns: BTreeSet<String>,
+}
+
+/// Collapse a set of observed type names into a single PEP 484 annotation,
+/// following MonkeyType's rules: a slot only ever seen as `NoneType` becomes
+/// `None`, a single type is used as-is, `NoneType` alongside a single other
+/// type becomes `Optional[T]`, and several other types become a `Union[..]`
+/// (wrapped as `Optional[Union[..]]` when `NoneType` was also seen).
+fn render_types(types: &BTreeSet<String>) -> String {
+    if types.is_empty() {
+        return String::from("Any");
+    }
+    let has_none = types.iter().any(|t| t == "NoneType");
+    let non_none: Vec<&str> = types
+        .iter()
+        .map(String::as_str)
+        .filter(|&t| t != "NoneType")
+        .collect();
+    match non_none.len() {
+        0 => String::from("None"),
+        1 if has_none => format!("Optional[{}]", non_none[0]),
+        1 => String::from(non_none[0]),
+        _ => {
+            let joined = non_none.join(", ");
+            if has_none {
+                format!("Optional[Union[{}]]", joined)
+            } else {
+                format!("Union[{}]", joined)
+            }
+        }
+    }
+}
+
+/// Merge the per-call records into one aggregate per `(filename, name, firstlineno)`,
+/// unioning the observed types for every argument slot and the return value.
+fn aggregate_frames(frames: &[FrameInfo]) -> BTreeMap<(String, String, i32), FunctionStub> {
+    let mut stubs: BTreeMap<(String, String, i32), FunctionStub> = BTreeMap::new();
+    for frame in frames {
+        // Key on the first line too, so same-named functions in one file don't
+        // merge into a single stub with their parameter types unioned together.
+        let key = (
+            frame.filename.clone(),
+            frame.name.clone(),
+            frame.firstlineno,
+        );
+        let stub = stubs.entry(key).or_insert_with(|| FunctionStub {
+            name: frame.name.clone(),
+            params: Vec::new(),
+            returns: BTreeSet::new(),
+        });
+        for (i, arg) in frame.args.iter().enumerate() {
+            match stub.params.get_mut(i) {
+                Some((_, _, types)) => {
+                    types.insert(arg.typ.clone());
+                }
+                None => {
+                    let mut types = BTreeSet::new();
+                    types.insert(arg.typ.clone());
+                    stub.params.push((arg.name.clone(), arg.kind, types));
+                }
+            }
+        }
+        // Raising calls record an empty return; don't union it into the stub.
+        if !frame.returns.is_empty() {
+            stub.returns.insert(frame.returns.clone());
+        }
+    }
+    stubs
+}
+
+/// Render a single aggregated function as a PEP 484 stub line, e.g.
+/// `def foo(x: Union[int, str]) -> None: ...`.
+fn render_stub(stub: &FunctionStub) -> String {
+    let mut parts = Vec::with_capacity(stub.params.len());
+    let has_star = stub
+        .params
+        .iter()
+        .any(|(_, kind, _)| *kind == ArgKind::StarArgs);
+    let mut inserted_bare_star = false;
+    for (name, kind, types) in &stub.params {
+        let anno = render_types(types);
+        match kind {
+            ArgKind::Positional => parts.push(format!("{}: {}", name, anno)),
+            ArgKind::StarArgs => parts.push(format!("*{}: {}", name, anno)),
+            ArgKind::KeywordOnly => {
+                // Keyword-only arguments need a bare `*` separator unless a
+                // real `*args` slot already introduced one.
+                if !has_star && !inserted_bare_star {
+                    parts.push(String::from("*"));
+                    inserted_bare_star = true;
+                }
+                parts.push(format!("{}: {}", name, anno));
+            }
+            ArgKind::StarKwargs => parts.push(format!("**{}: {}", name, anno)),
+        }
+    }
+    format!(
+        "def {}({}) -> {}: ...",
+        stub.name,
+        parts.join(", "),
+        render_types(&stub.returns)
+    )
+}
+
+/// Turn a source filename into the `.pyi` stub file name it should emit to.
+/// The path (relative to the current directory when it lives underneath it) is
+/// folded into a dotted module name, so `pkg_a/util.py` and `pkg_b/util.py`
+/// land in distinct `pkg_a.util.pyi` / `pkg_b.util.pyi` files instead of
+/// silently merging into a shared `util.pyi`.
+fn stub_filename(filename: &str) -> String {
+    let path = Path::new(filename);
+    let rel = path.strip_prefix(CURRENT_DIR.as_path()).unwrap_or(path);
+    let parts: Vec<String> = rel
+        .with_extension("")
+        .components()
+        .filter_map(|c| match c {
+            std::path::Component::Normal(s) => s.to_str().map(String::from),
+            _ => None,
+        })
+        .collect();
+    if parts.is_empty() {
+        String::from("module.pyi")
+    } else {
+        format!("{}.pyi", parts.join("."))
+    }
+}
+
+/// Aggregate every recorded frame and write one `.pyi` stub per module into
+/// `dir`, grouping functions observed in the same source file.
+fn write_stubs(frames: &[FrameInfo], dir: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let stubs = aggregate_frames(frames);
+    // Group the rendered stubs back together by source module.
+    let mut modules: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for ((filename, _, _), stub) in &stubs {
+        modules
+            .entry(stub_filename(filename))
+            .or_insert_with(Vec::new)
+            .push(render_stub(stub));
+    }
+    for (name, lines) in modules {
+        let mut file = fs::File::create(dir.join(name))?;
+        writeln!(
+            file,
+            "from typing import Any, Dict, FrozenSet, List, Optional, Set, Tuple, Union"
+        )?;
+        writeln!(file)?;
+        for line in lines {
+            writeln!(file, "{}", line)?;
+        }
+    }
+    Ok(())
+}
+
+/// Convert a single recorded frame into a native Python `dict`, so callers can
+/// post-process traces in-process without parsing a log file.
+fn frame_to_py(py: Python, frame: &FrameInfo) -> PyResult<PyObject> {
+    let dict = PyDict::new(py);
+    dict.set_item("name", &frame.name)?;
+    dict.set_item("filename", &frame.filename)?;
+    dict.set_item("returns", &frame.returns)?;
+    dict.set_item("raises", &frame.raises)?;
+    let args = PyList::empty(py);
+    for arg in frame.args.iter() {
+        let item = PyDict::new(py);
+        item.set_item("name", &arg.name)?;
+        item.set_item("typ", &arg.typ)?;
+        item.set_item("kind", format!("{:?}", arg.kind))?;
+        args.append(item)?;
+    }
+    dict.set_item("args", args)?;
+    Ok(dict.to_object(py))
+}
+
+/// Get the type of a Python object pointer, recursively sampling container
+/// element types (see [`describe_obj`]).
+fn get_type<'a>(py: Python<'a>, obj: *mut PyObject) -> String {
     match unsafe { py.from_borrowed_ptr_or_opt::<PyObjectRef>(obj) } {
-        Some(typ) => typ.get_type().name(),
-        None => Cow::from("<unknown>"),
+        Some(typ) => describe_obj(typ, 0),
+        None => String::from("<unknown>"),
     }
 }
 
@@ -183,8 +756,7 @@ unsafe extern "C" fn frame_printer(frame: *mut PyFrameObject, exc: c_int) -> *mu
     let name = cname.deref();
     let file = cfile.deref();
 
-    let cwd = CURRENT_DIR.to_str().unwrap();
-    if &name[..1usize] != "<" && (file.starts_with(cwd) || file == "<stdin>") {
+    if CONFIG.read().unwrap().should_trace(name, file) {
         let locals_name = CString::new("f_locals").unwrap();
         let frame_locals = PyObject_GetAttrString(frame as *mut PyObject, locals_name.as_ptr());
         let locals = match py.from_borrowed_ptr_or_opt::<PyObjectRef>(frame_locals) {
@@ -192,21 +764,49 @@ unsafe extern "C" fn frame_printer(frame: *mut PyFrameObject, exc: c_int) -> *mu
             None => &PyDict::new(py),
         };
         let ret = _PyEval_EvalFrameDefault(frame, exc);
-        let ret_ty = get_type(py, ret);
+        // A null return signals a propagating exception. Fetch it so we can
+        // record the class name, then restore it untouched so the program's
+        // error handling behaves exactly as it would have.
+        let raises = if ret.is_null() {
+            let err = PyErr::fetch(py);
+            let name = exc_type_name(py, &err);
+            err.restore(py);
+            name
+        } else {
+            None
+        };
+        // A raising call has no return value, so leave `returns` empty rather
+        // than recording the `<unknown>` placeholder `get_type` would produce
+        // for the null pointer; `aggregate_frames` drops the empty observation.
+        let ret_ty = if ret.is_null() {
+            String::new()
+        } else {
+            get_type(py, ret)
+        };
         let info = FrameInfo::new(
             name,
             file,
-            ret_ty.deref(),
+            &ret_ty,
             locals,
             code_obj.co_argcount,
             code_obj.co_kwonlyargcount,
             code_obj.co_flags,
+            raises,
+            code_obj.co_firstlineno,
         );
-        let frames = match FRAMES.as_mut() {
-            Some(frame) => frame.get_mut().unwrap(),
-            None => panic!("Failed to get frames"),
-        };
-        frames.push(info);
+        let full = LOCAL_FRAMES.with(|buf| {
+            let mut frames = buf.lock().unwrap();
+            frames.push(info);
+            frames.len() >= DRAIN_INTERVAL
+        });
+        // Drain the per-thread buffers into the central store periodically, not
+        // only at exit, so the buffers stay bounded and the store (and, for the
+        // SQLite backend, the disk) sees a steady stream of records even if the
+        // process never exits cleanly.
+        if full {
+            let mut store = store_lock();
+            drain_buffers(&mut store);
+        }
 
         ret
     } else {
@@ -221,20 +821,10 @@ struct DummyCallback {}
 impl DummyCallback {
     #[call]
     fn __call__(&self) -> PyResult<()> {
-        let logger = {
-            let mut builder = FileLoggerBuilder::new("test.log");
-            builder.level(Severity::Info);
-            builder.overflow_strategy(OverflowStrategy::Block);
-            builder.channel_size(4096);
-            builder.build().unwrap()
-        };
         unsafe {
-            let frames = match FRAMES.as_mut() {
-                Some(frame) => frame.get_mut().unwrap(),
-                None => panic!("Failed to get frames"),
-            };
-            info!(logger, "{}", serde_json::to_string(frames).unwrap());
-            info!(logger, "Captured {} frames", frames.len());
+            let mut store = store_lock();
+            drain_buffers(&mut store);
+            store.flush();
         }
         Ok(())
     }
@@ -242,12 +832,12 @@ impl DummyCallback {
 
 #[pymodule]
 fn pytrace_native(py: Python, m: &PyModule) -> PyResult<()> {
-    // We start with creating a vec to store frames. This vec gets dumped at
-    // the end of program execution.
-    // This actually gives a huge performance improvement, as we can turn millions
-    // of small writes into one large one (a > 2.5x speedup!).
+    // Install the default in-memory JSON store. `hook` may swap in another
+    // backend (e.g. SQLite) before tracing starts. Buffering records and
+    // dumping once gives a huge performance improvement, as we can turn
+    // millions of small writes into one large one (a > 2.5x speedup!).
     unsafe {
-        FRAMES = Some(Mutex::new(Vec::new()));
+        STORE = Some(Mutex::new(Box::new(JsonStore::new(String::from("test.log")))));
     }
     // This code registers the function to dump the frame data at the end.
     // We need to use a dummy class because we can't pass functions across
@@ -256,26 +846,125 @@ fn pytrace_native(py: Python, m: &PyModule) -> PyResult<()> {
     let dummy = DummyCallback {};
     atexit.call("register", (dummy,), None)?;
 
-    /// Hook into the Python interpreter
+    /// Compile a list of glob strings into patterns, surfacing a malformed
+    /// pattern as a Python `ValueError`.
+    fn compile_patterns(patterns: Option<Vec<String>>) -> PyResult<Vec<Pattern>> {
+        patterns
+            .unwrap_or_default()
+            .iter()
+            .map(|p| {
+                Pattern::new(p)
+                    .map_err(|e| PyErr::new::<pyo3::exceptions::ValueError, _>(e.to_string()))
+            })
+            .collect()
+    }
+
+    /// Hook into the Python interpreter, selecting the trace backend and the
+    /// capture filter. `backend` is `"json"` (the default) or `"sqlite"`;
+    /// `path` is the target log file or database. `include` / `exclude` are
+    /// glob patterns matched against frame filenames, and `names` restricts
+    /// capture to matching function names (module names are not matched); all
+    /// default to empty, which
+    /// traces frames under the current working directory.
     #[pyfn(m, "hook")]
-    fn hook(_py: Python) -> PyResult<()> {
+    fn hook(
+        _py: Python,
+        backend: Option<String>,
+        path: Option<String>,
+        include: Option<Vec<String>>,
+        exclude: Option<Vec<String>>,
+        names: Option<Vec<String>>,
+    ) -> PyResult<()> {
+        *CONFIG.write().unwrap() = TraceConfig {
+            include: compile_patterns(include)?,
+            exclude: compile_patterns(exclude)?,
+            names: compile_patterns(names)?,
+        };
+        let backend = backend.unwrap_or_else(|| String::from("json"));
+        let store: Box<dyn TraceStore> = match backend.as_str() {
+            "sqlite" => {
+                let path = path.unwrap_or_else(|| String::from("trace.db"));
+                let store = SqliteStore::open(&path)
+                    .map_err(|e| PyErr::new::<pyo3::exceptions::IOError, _>(e.to_string()))?;
+                Box::new(store)
+            }
+            "json" => Box::new(JsonStore::new(
+                path.unwrap_or_else(|| String::from("test.log")),
+            )),
+            other => {
+                return Err(PyErr::new::<pyo3::exceptions::ValueError, _>(format!(
+                    "unknown trace backend: {}",
+                    other
+                )))
+            }
+        };
+        unsafe {
+            *store_lock() = store;
+        }
         cpp!(unsafe [] {
-            PyThreadState *state = PyThreadState_Get();
-            _PyFrameEvalFunction func = state->interp->eval_frame;
-            state->interp->eval_frame = rust!(
+            _PyFrameEvalFunction func = rust!(
                 fprinter [] -> _PyFrameEvalFunction as "_PyFrameEvalFunction" {
                     frame_printer
                 });
+            // Install on every interpreter, not just the current one, so
+            // sub-interpreters are traced too.
+            PyInterpreterState *interp = PyInterpreterState_Head();
+            while (interp != NULL) {
+                interp->eval_frame = func;
+                interp = PyInterpreterState_Next(interp);
+            }
         });
         Ok(())
     }
 
+    /// Emit MonkeyType-style PEP 484 stubs for everything collected so far.
+    /// Writes one `.pyi` file per traced module into the directory `path`.
+    #[pyfn(m, "dump_stubs")]
+    fn dump_stubs(_py: Python, path: &str) -> PyResult<()> {
+        let frames = unsafe {
+            let mut store = store_lock();
+            drain_buffers(&mut store);
+            store.snapshot()
+        };
+        write_stubs(&frames, Path::new(path))
+            .map_err(|e| PyErr::new::<pyo3::exceptions::IOError, _>(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Return the collected traces as native Python objects (a list of dicts),
+    /// so results can be consumed in-process without reading a log file.
+    #[pyfn(m, "get_frames")]
+    fn get_frames(py: Python) -> PyResult<Vec<PyObject>> {
+        let frames = unsafe {
+            let mut store = store_lock();
+            drain_buffers(&mut store);
+            store.snapshot()
+        };
+        frames.iter().map(|f| frame_to_py(py, f)).collect()
+    }
+
+    /// Clear all collected traces so a fresh trace session can run in the same
+    /// interpreter.
+    #[pyfn(m, "reset")]
+    fn reset(_py: Python) -> PyResult<()> {
+        for buf in BUFFERS.lock().unwrap().iter() {
+            buf.lock().unwrap().clear();
+        }
+        unsafe {
+            store_lock().clear();
+        }
+        Ok(())
+    }
+
     /// Unhook from the Python interpreter
     #[pyfn(m, "unhook")]
     fn unhook(_py: Python) -> PyResult<()> {
         cpp!(unsafe [] {
-            PyThreadState *state = PyThreadState_Get();
-            state->interp->eval_frame = _PyEval_EvalFrameDefault;
+            PyInterpreterState *interp = PyInterpreterState_Head();
+            while (interp != NULL) {
+                interp->eval_frame = _PyEval_EvalFrameDefault;
+                interp = PyInterpreterState_Next(interp);
+            }
         });
         Ok(())
     }